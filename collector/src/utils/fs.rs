@@ -1,8 +1,16 @@
 use anyhow::Context;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs;
-use std::path::{Component, Path};
+use std::io;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
 
 #[cfg(windows)]
 pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> anyhow::Result<()> {
@@ -14,31 +22,175 @@ pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> anyhow::Result<
         return Ok(fs::rename(from, to).with_context(|| ctx.clone())?);
     }
 
-    robocopy(from, to, &[&"/move"]).with_context(|| ctx.clone())
+    if fs::rename(from, to).is_err() {
+        copy_recursive_preserving_times(from, to).with_context(|| ctx.clone())?;
+        remove_copied_source(from).with_context(|| ctx.clone())?;
+    }
+
+    Ok(())
 }
 
 #[cfg(unix)]
 pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(from: P, to: Q) -> anyhow::Result<()> {
     let (from, to) = (from.as_ref(), to.as_ref());
     if fs::rename(from, to).is_err() {
-        // This is necessary if from and to are on different
-        // mount points (e.g., if /tmp is in tmpfs instead of on
-        // the same disk). We don't want to implement a full recursive solution
-        // to copying directories, so just shell out to `mv`.
-        let ctx = format!("mv {:?} {:?}", from, to);
-        let status = Command::new("mv")
-            .arg(from)
-            .arg(to)
-            .status()
-            .with_context(|| ctx.clone())?;
-        if !status.success() {
-            anyhow::bail!("mv {:?} {:?}: {:?}", from, to, status);
+        // This is necessary if from and to are on different mount points
+        // (e.g., if /tmp is in tmpfs instead of on the same disk). Recreate
+        // the tree natively instead of shelling out to `mv`, so file mtimes
+        // survive the move: the wrapped rustc relies on them for incremental
+        // invalidation, and a cross-filesystem `mv` doesn't reliably keep them.
+        let ctx = format!("moving {:?} to {:?}", from, to);
+        copy_recursive_preserving_times(from, to).with_context(|| ctx.clone())?;
+        remove_copied_source(from).with_context(|| ctx.clone())?;
+    }
+
+    Ok(())
+}
+
+/// Removes `from` after it's been recreated at the destination by
+/// [`copy_recursive_preserving_times`]. `from` may be a plain file (not just
+/// a directory) when it's the thing being moved, so `remove_dir_all` alone
+/// would fail on it; dispatch on what it actually is.
+fn remove_copied_source(from: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(from)?.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    }
+}
+
+/// Recursively copies `from` to `to`, restoring each entry's atime and mtime
+/// from the source afterwards. Used as a native fallback for moves that
+/// `fs::rename` can't perform directly (e.g. across mount points or
+/// volumes), so neither platform needs to shell out to `mv`/`robocopy`.
+///
+/// Symlinks are recreated as symlinks rather than being followed: build and
+/// toolchain trees commonly contain them (e.g. `target/` artifacts or
+/// toolchain `bin/` links), and copying through a link both bloats the copy
+/// and can break links whose target is relative.
+fn copy_recursive_preserving_times(from: &Path, to: &Path) -> anyhow::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+
+    if metadata.is_dir() {
+        fs::create_dir_all(to).with_context(|| format!("creating directory {:?}", to))?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursive_preserving_times(&entry.path(), &to.join(entry.file_name()))?;
+        }
+
+        let atime = filetime::FileTime::from_last_access_time(&metadata);
+        let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+        filetime::set_file_times(to, atime, mtime)
+            .with_context(|| format!("restoring timestamps on {:?}", to))?;
+    } else if metadata.is_symlink() {
+        let target =
+            fs::read_link(from).with_context(|| format!("reading symlink {:?}", from))?;
+        create_symlink(&target, to, from)
+            .with_context(|| format!("recreating symlink {:?} -> {:?}", to, target))?;
+
+        let atime = filetime::FileTime::from_last_access_time(&metadata);
+        let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+        filetime::set_symlink_file_times(to, atime, mtime)
+            .with_context(|| format!("restoring timestamps on symlink {:?}", to))?;
+    } else {
+        fs::copy(from, to).with_context(|| format!("copying {:?} to {:?}", from, to))?;
+
+        let atime = filetime::FileTime::from_last_access_time(&metadata);
+        let mtime = filetime::FileTime::from_last_modification_time(&metadata);
+        filetime::set_file_times(to, atime, mtime)
+            .with_context(|| format!("restoring timestamps on {:?}", to))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path, _original: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path, original: &Path) -> io::Result<()> {
+    // Windows distinguishes file and directory symlinks. Resolving through
+    // `original` (the symlink being copied) rather than `target` handles a
+    // relative target correctly, since it's resolved relative to `original`.
+    if fs::metadata(original).map(|m| m.is_dir()).unwrap_or(false) {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+/// Atomically swaps a freshly-prepared staging directory into `target`'s
+/// place, leaving `target` either fully replaced or fully untouched — never
+/// observed half-written by another reader.
+///
+/// On Linux this is a single `renameat2(..., RENAME_EXCHANGE)` syscall: it
+/// swaps `staging` and `target` in place, after which the now-stale tree
+/// (sitting at `staging`'s path) is deleted. Other platforms fall back to
+/// renaming `target` aside, renaming `staging` into `target`, then deleting
+/// the aside copy; this is no longer atomic, but `target` is never removed
+/// before its replacement has already taken its place.
+pub fn replace_dir(staging: &Path, target: &Path) -> anyhow::Result<()> {
+    let ctx = || format!("replacing directory {:?} with {:?}", target, staging);
+
+    #[cfg(target_os = "linux")]
+    {
+        if renameat2_exchange(staging, target).is_ok() {
+            return fs::remove_dir_all(staging).with_context(ctx);
         }
+        // The exchange failed (`renameat2` isn't available on an old kernel,
+        // the filesystem doesn't support it, or `target` doesn't exist yet):
+        // fall through to the portable path below.
     }
 
+    replace_dir_fallback(staging, target).with_context(ctx)
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2_exchange(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let to_cstring =
+        |p: &Path| CString::new(p.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e));
+    let a = to_cstring(a)?;
+    let b = to_cstring(b)?;
+
+    // SAFETY: `a` and `b` are valid, NUL-terminated paths for the lifetime of
+    // this call.
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
     Ok(())
 }
 
+fn replace_dir_fallback(staging: &Path, target: &Path) -> anyhow::Result<()> {
+    if !target.exists() {
+        return fs::rename(staging, target)
+            .with_context(|| format!("renaming {:?} into place at {:?}", staging, target));
+    }
+
+    let mut old = target.as_os_str().to_owned();
+    old.push(".old");
+    let old = PathBuf::from(old);
+
+    fs::rename(target, &old).with_context(|| format!("moving {:?} aside to {:?}", target, old))?;
+    fs::rename(staging, target)
+        .with_context(|| format!("renaming {:?} into place at {:?}", staging, target))?;
+    fs::remove_dir_all(&old).with_context(|| format!("removing old directory {:?}", old))
+}
+
 /// Touch a file, resetting its modification time.
 pub fn touch(path: &Path) -> anyhow::Result<()> {
     log::trace!("touching file {:?}", path);
@@ -49,38 +201,112 @@ pub fn touch(path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Reset the modification time of all files in the given path.
-pub fn touch_all(path: &Path) -> anyhow::Result<()> {
-    fn is_valid(path: &Path) -> bool {
-        let target_dir = Component::Normal(OsStr::new("target"));
+/// A compiled set of include/exclude glob patterns deciding which files
+/// [`touch_all_with_policy`] touches, plus an optional separate set of
+/// patterns for files that should be deleted outright (e.g. stale build
+/// caches) rather than touched.
+///
+/// Patterns are compiled once into [`globset::GlobSet`]s, so matching a path
+/// is a single pass per set rather than a chain of ad-hoc checks. Within the
+/// `touch` patterns, a pattern prefixed with `!` excludes a path: a path is
+/// touched iff at least one non-`!` pattern matches it and no `!` pattern
+/// does. Include and exclude patterns are compiled into separate `GlobSet`s
+/// and checked explicitly, rather than relying on the order `GlobSet::matches`
+/// happens to return matches in (it groups matches by internal strategy —
+/// literal, basename, extension, regex — not by the order patterns were
+/// added, so "last match wins" can't be implemented by scanning that list).
+pub struct TouchPolicy {
+    include: globset::GlobSet,
+    exclude: globset::GlobSet,
+    delete: Option<globset::GlobSet>,
+}
 
-        // Don't touch files in `target/`, since they're likely generated by build scripts and might be from a dependency.
-        if path.components().any(|component| component == target_dir) {
-            return false;
+impl TouchPolicy {
+    /// Builds a policy from `touch` patterns (see [`TouchPolicy`] for the
+    /// `!`-exclude convention) and `delete` patterns (matched independently,
+    /// with no exclude support).
+    pub fn new<'a>(
+        touch: impl IntoIterator<Item = &'a str>,
+        delete: impl IntoIterator<Item = &'a str>,
+    ) -> anyhow::Result<Self> {
+        let mut include_builder = globset::GlobSetBuilder::new();
+        let mut exclude_builder = globset::GlobSetBuilder::new();
+        for pattern in touch {
+            let (pattern, is_exclude) = match pattern.strip_prefix('!') {
+                Some(rest) => (rest, true),
+                None => (pattern, false),
+            };
+            let glob = globset::Glob::new(pattern)
+                .with_context(|| format!("invalid glob pattern {:?}", pattern))?;
+            if is_exclude {
+                exclude_builder.add(glob);
+            } else {
+                include_builder.add(glob);
+            }
         }
 
-        if let Some(extn) = path.extension() {
-            if extn.to_str() == Some("rs") {
-                // Don't touch build scripts, which confuses the wrapped rustc.
-                return path.file_name() != Some(OsStr::new("build.rs"));
-            }
+        let mut delete_builder = globset::GlobSetBuilder::new();
+        let mut has_delete_patterns = false;
+        for pattern in delete {
+            has_delete_patterns = true;
+            delete_builder.add(
+                globset::Glob::new(pattern)
+                    .with_context(|| format!("invalid glob pattern {:?}", pattern))?,
+            );
         }
 
-        false
+        Ok(Self {
+            include: include_builder.build().context("building touch glob set")?,
+            exclude: exclude_builder
+                .build()
+                .context("building touch exclude glob set")?,
+            delete: has_delete_patterns
+                .then(|| delete_builder.build())
+                .transpose()
+                .context("building delete glob set")?,
+        })
+    }
+
+    fn should_touch(&self, path: &Path) -> bool {
+        self.include.is_match(path) && !self.exclude.is_match(path)
     }
 
+    fn should_delete(&self, path: &Path) -> bool {
+        self.delete.as_ref().is_some_and(|set| set.is_match(path))
+    }
+}
+
+impl Default for TouchPolicy {
+    /// `touch_all`'s previous hardcoded behavior: touch `*.rs` files, except
+    /// `build.rs` (which would confuse the wrapped rustc), skip `target/`
+    /// (likely generated by build scripts or a dependency), and delete stray
+    /// `CMakeCache.txt` files that would otherwise break moving directories
+    /// around.
+    fn default() -> Self {
+        TouchPolicy::new(["**/*.rs", "!**/build.rs", "!**/target/**"], ["**/CMakeCache.txt"])
+            .expect("default glob patterns are valid")
+    }
+}
+
+/// Reset the modification time of all files in the given path.
+pub fn touch_all(path: &Path) -> anyhow::Result<()> {
+    touch_all_with_policy(path, &TouchPolicy::default())
+}
+
+/// Like [`touch_all`], but with a caller-supplied [`TouchPolicy`] instead of
+/// the default `*.rs`-only one, so build systems other than Cargo's can
+/// touch e.g. `.c`/`.h` sources and clear their own stale caches.
+pub fn touch_all_with_policy(path: &Path, policy: &TouchPolicy) -> anyhow::Result<()> {
     for entry in walkdir::WalkDir::new(path) {
         let entry = entry?;
         let path = entry.path();
 
-        // We also delete the cmake caches to avoid errors when moving directories around.
-        // This might be a bit slower but at least things build
-        if path.file_name() == Some(OsStr::new("CMakeCache.txt")) {
-            fs::remove_file(path)
-                .with_context(|| format!("deleting cmake caches in {:?}", path))?;
+        if policy.should_delete(path) {
+            fs::remove_file(path).with_context(|| format!("deleting stale file {:?}", path))?;
+            continue;
         }
 
-        if is_valid(path) {
+        if policy.should_touch(path) {
             touch(path)?;
         }
     }
@@ -88,28 +314,440 @@ pub fn touch_all(path: &Path) -> anyhow::Result<()> {
     Ok(())
 }
 
-/// Counts the number of files and the total size of all files within the given `path`.
-/// File size is counted as the actual size in bytes, i.e. the size returned by
-/// [std::path::Path::metadata].
-///
-/// Returns (file_count, size).
-pub fn get_file_count_and_size(path: &Path) -> std::io::Result<(u64, u64)> {
-    let (count, size) = if path.is_dir() {
-        let mut file_count = 0;
-        let mut total_size = 0;
-        for entry in fs::read_dir(&path)? {
-            let path = entry?.path();
-            let (count, size) = get_file_count_and_size(&path)?;
-            file_count += count;
-            total_size += size;
+/// Controls how [`archive`] compresses its output.
+pub struct ArchiveOptions {
+    /// xz compression preset/level, 0-9.
+    pub level: u32,
+    /// LZMA dictionary (window) size, in bytes. A larger window shrinks the
+    /// compressed size of large build trees considerably, at the cost of
+    /// higher peak memory use when decompressing.
+    pub dict_size: u32,
+}
+
+impl Default for ArchiveOptions {
+    fn default() -> Self {
+        Self {
+            level: 6,
+            // 64 MiB: a good trade-off for toolchain/artifact-sized trees.
+            dict_size: 64 * 1024 * 1024,
         }
-        (file_count, total_size)
-    } else if path.is_file() {
-        (1, path.metadata()?.len())
-    } else {
-        (0, 0)
+    }
+}
+
+fn is_archivable(root: &Path, path: &Path) -> bool {
+    let target_dir = Component::Normal(OsStr::new("target"));
+    let under_target = path
+        .strip_prefix(root)
+        .map(|relative| relative.components().any(|component| component == target_dir))
+        .unwrap_or(false);
+
+    !under_target && path.file_name() != Some(OsStr::new("CMakeCache.txt"))
+}
+
+/// Packs `dir` into a `.tar.xz` archive at `out`, so built toolchains and
+/// artifacts can be cached compactly and moved between machines without
+/// relying on external `tar`/`xz` binaries. Entries are skipped the same way
+/// [`touch_all`] skips them (`target/` and cmake caches), and file mtimes are
+/// preserved in the tar so extracting reproduces timestamps.
+pub fn archive(dir: &Path, out: &Path, opts: &ArchiveOptions) -> anyhow::Result<()> {
+    let mut lzma_opts = xz2::stream::LzmaOptions::new_preset(opts.level)
+        .with_context(|| format!("configuring xz preset {}", opts.level))?;
+    lzma_opts.dict_size(opts.dict_size);
+
+    let mut filters = xz2::stream::Filters::new();
+    filters.lzma2(&lzma_opts);
+    let stream = xz2::stream::Stream::new_stream_encoder(&filters, xz2::stream::Check::Crc64)
+        .context("creating xz encoder")?;
+
+    let file = fs::File::create(out).with_context(|| format!("creating archive {:?}", out))?;
+    let mut builder = tar::Builder::new(xz2::write::XzEncoder::new_stream(file, stream));
+
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(dir).unwrap();
+
+        if relative.as_os_str().is_empty() || !is_archivable(dir, path) {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            builder
+                .append_dir(relative, path)
+                .with_context(|| format!("archiving directory {:?}", path))?;
+        } else if entry.file_type().is_symlink() {
+            // Store the link itself rather than opening through it: the
+            // target may be a directory (which `File::open` can't read) or
+            // dangling (which it can't find), and either way dereferencing
+            // it here would silently turn the link into a plain file.
+            let target =
+                fs::read_link(path).with_context(|| format!("reading symlink {:?}", path))?;
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&entry.metadata()?);
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder
+                .append_link(&mut header, relative, &target)
+                .with_context(|| format!("archiving symlink {:?}", path))?;
+        } else {
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&entry.metadata()?);
+            let mut file =
+                fs::File::open(path).with_context(|| format!("opening {:?}", path))?;
+            builder
+                .append_data(&mut header, relative, &mut file)
+                .with_context(|| format!("archiving {:?}", path))?;
+        }
+    }
+
+    builder
+        .into_inner()
+        .context("finishing tar stream")?
+        .finish()
+        .context("finishing xz stream")?;
+
+    Ok(())
+}
+
+/// Unpacks a `.tar.xz` archive produced by [`archive`] into `dir`, restoring
+/// each entry's modification time.
+pub fn extract(archive: &Path, dir: &Path) -> anyhow::Result<()> {
+    let file =
+        fs::File::open(archive).with_context(|| format!("opening archive {:?}", archive))?;
+    let mut tar = tar::Archive::new(xz2::read::XzDecoder::new(file));
+    tar.set_preserve_mtime(true);
+    tar.unpack(dir)
+        .with_context(|| format!("extracting {:?} into {:?}", archive, dir))?;
+
+    Ok(())
+}
+
+/// Number of workers used to walk a directory tree in parallel in [`dir_stats`].
+const DIR_STATS_WORKERS: usize = 5;
+
+/// File count, apparent size (the sum of [`std::fs::Metadata::len`]), real
+/// size on disk (the sum of allocated blocks) and newest modification time
+/// (seconds since the Unix epoch) of a directory tree. On Unix, hard-linked
+/// files are only counted once, and sparse files are reflected in `real_size`
+/// being smaller than `apparent_size`. On other platforms hard links are not
+/// detected and `real_size` equals `apparent_size`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DirStats {
+    pub file_count: u64,
+    pub apparent_size: u64,
+    pub real_size: u64,
+    pub newest_mtime: u64,
+}
+
+impl DirStats {
+    fn merge(&mut self, other: DirStats) {
+        self.file_count += other.file_count;
+        self.apparent_size += other.apparent_size;
+        self.real_size += other.real_size;
+        self.newest_mtime = self.newest_mtime.max(other.newest_mtime);
+    }
+}
+
+fn mtime_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Per-directory node state used while a [`dir_stats`] walk is in flight.
+/// A node is fully resolved once it has been `scanned` and all of its
+/// subdirectories (`pending_children`) have themselves resolved.
+struct PendingDir {
+    parent: Option<PathBuf>,
+    pending_children: usize,
+    scanned: bool,
+    stats: DirStats,
+}
+
+fn dir_stats_cache() -> &'static Mutex<HashMap<PathBuf, DirStats>> {
+    static CACHE: OnceLock<Mutex<HashMap<PathBuf, DirStats>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Drop all memoized [`dir_stats`] results. Call this once a directory tree
+/// that has previously been queried is known to have changed on disk.
+pub fn clear_cache() {
+    dir_stats_cache().lock().unwrap().clear();
+}
+
+#[cfg(unix)]
+fn account_file(metadata: &fs::Metadata, seen_inodes: &mut HashSet<(u64, u64)>, stats: &mut DirStats) {
+    use std::os::unix::fs::MetadataExt;
+
+    // A link count > 1 means this file is reachable through more than one
+    // directory entry; only count it the first time we see its (dev, ino).
+    if metadata.nlink() > 1 && !seen_inodes.insert((metadata.dev(), metadata.ino())) {
+        return;
+    }
+
+    stats.file_count += 1;
+    stats.apparent_size += metadata.len();
+    // Real size on disk, which is smaller than `len()` for sparse files.
+    stats.real_size += metadata.blocks() * 512;
+    stats.newest_mtime = stats.newest_mtime.max(mtime_secs(metadata));
+}
+
+#[cfg(not(unix))]
+fn account_file(metadata: &fs::Metadata, _seen_inodes: &mut HashSet<(u64, u64)>, stats: &mut DirStats) {
+    stats.file_count += 1;
+    stats.apparent_size += metadata.len();
+    stats.real_size += metadata.len();
+    stats.newest_mtime = stats.newest_mtime.max(mtime_secs(metadata));
+}
+
+/// Marks `dir` as resolved with the given `stats`, caches it, and propagates
+/// the result into its parent, recursively completing ancestors whose last
+/// pending child was `dir`.
+fn complete_dir(
+    dir: &Path,
+    stats: DirStats,
+    parent: Option<PathBuf>,
+    nodes: &Mutex<HashMap<PathBuf, PendingDir>>,
+    use_cache: bool,
+) {
+    if use_cache {
+        dir_stats_cache().lock().unwrap().insert(dir.to_path_buf(), stats);
+    }
+
+    let Some(parent) = parent else {
+        return;
+    };
+
+    let mut nodes_guard = nodes.lock().unwrap();
+    let parent_node = nodes_guard
+        .get_mut(&parent)
+        .expect("parent node is registered before its children");
+    parent_node.stats.merge(stats);
+    parent_node.pending_children -= 1;
+
+    if parent_node.scanned && parent_node.pending_children == 0 {
+        let parent_stats = parent_node.stats;
+        let grandparent = parent_node.parent.clone();
+        drop(nodes_guard);
+        complete_dir(&parent, parent_stats, grandparent, nodes, use_cache);
+    }
+}
+
+fn visit_dir(
+    dir: &Path,
+    tx: &Sender<PathBuf>,
+    busy: &AtomicUsize,
+    nodes: &Arc<Mutex<HashMap<PathBuf, PendingDir>>>,
+    seen_inodes: &Mutex<HashSet<(u64, u64)>>,
+    use_cache: bool,
+) -> io::Result<()> {
+    let mut local_stats = DirStats::default();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+
+        if metadata.is_dir() {
+            let cached = use_cache
+                .then(|| dir_stats_cache().lock().unwrap().get(&path).copied())
+                .flatten();
+            if let Some(cached) = cached {
+                // Already have a memoized result for this subtree: fold it
+                // in directly instead of re-walking it.
+                local_stats.merge(cached);
+                continue;
+            }
+
+            // Register the child and account for it in the parent's
+            // `pending_children` immediately, under the same lock. A child
+            // can be picked up and completed by another worker as soon as
+            // it's sent, so `pending_children` must already reflect it
+            // before that can happen — it must never be batch-assigned
+            // after the loop, which would race with in-flight completions.
+            {
+                let mut nodes_guard = nodes.lock().unwrap();
+                nodes_guard.insert(
+                    path.clone(),
+                    PendingDir {
+                        parent: Some(dir.to_path_buf()),
+                        pending_children: 0,
+                        scanned: false,
+                        stats: DirStats::default(),
+                    },
+                );
+                nodes_guard
+                    .get_mut(dir)
+                    .expect("node is registered before being visited")
+                    .pending_children += 1;
+            }
+            busy.fetch_add(1, Ordering::SeqCst);
+            tx.send(path).expect("a worker is always alive to receive this");
+        } else if metadata.is_file() {
+            account_file(&metadata, &mut seen_inodes.lock().unwrap(), &mut local_stats);
+        }
+    }
+
+    let (stats, parent) = {
+        let mut nodes_guard = nodes.lock().unwrap();
+        let node = nodes_guard
+            .get_mut(dir)
+            .expect("node is registered before being visited");
+        node.stats.merge(local_stats);
+        node.scanned = true;
+
+        if node.pending_children != 0 {
+            return Ok(());
+        }
+        (node.stats, node.parent.clone())
     };
-    Ok((count, size))
+
+    complete_dir(dir, stats, parent, nodes, use_cache);
+    Ok(())
+}
+
+/// Computes [`DirStats`] for `path`, traversing subdirectories with a small
+/// pool of worker threads fed by an MPMC channel of pending directory paths.
+/// An atomic "busy" counter tracks directories that have been handed out but
+/// not yet processed, so idle workers can tell a momentarily empty channel
+/// apart from a fully drained one. Results are memoized per directory in a
+/// process-wide cache (see [`clear_cache`]), so overlapping queries made
+/// during the same run don't re-stat subtrees they've already seen.
+pub fn dir_stats(path: &Path) -> io::Result<DirStats> {
+    dir_stats_impl(path, true)
+}
+
+/// Like [`dir_stats`], but always performs a fresh traversal and never reads
+/// or populates the memoization cache.
+///
+/// `touch_all` changes file mtimes without invalidating [`dir_stats_cache`],
+/// so anything that decides "did this tree change since the last touch?"
+/// from `newest_mtime` must not read a cached `DirStats` — it would keep
+/// reporting the pre-touch mtime and never see the tree as changed.
+fn dir_stats_uncached(path: &Path) -> io::Result<DirStats> {
+    dir_stats_impl(path, false)
+}
+
+fn dir_stats_impl(path: &Path, use_cache: bool) -> io::Result<DirStats> {
+    if use_cache {
+        if let Some(cached) = dir_stats_cache().lock().unwrap().get(path).copied() {
+            return Ok(cached);
+        }
+    }
+
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        let mut stats = DirStats::default();
+        if metadata.is_file() {
+            account_file(&metadata, &mut HashSet::new(), &mut stats);
+        }
+        return Ok(stats);
+    }
+
+    let (tx, rx): (Sender<PathBuf>, Receiver<PathBuf>) = unbounded();
+    let busy = Arc::new(AtomicUsize::new(1));
+    let nodes: Arc<Mutex<HashMap<PathBuf, PendingDir>>> = Arc::new(Mutex::new(HashMap::new()));
+    let seen_inodes: Arc<Mutex<HashSet<(u64, u64)>>> = Arc::new(Mutex::new(HashSet::new()));
+    let error: Arc<Mutex<Option<io::Error>>> = Arc::new(Mutex::new(None));
+
+    nodes.lock().unwrap().insert(
+        path.to_path_buf(),
+        PendingDir {
+            parent: None,
+            pending_children: 0,
+            scanned: false,
+            stats: DirStats::default(),
+        },
+    );
+    tx.send(path.to_path_buf())
+        .expect("a worker is always alive to receive this");
+
+    let workers: Vec<_> = (0..DIR_STATS_WORKERS)
+        .map(|_| {
+            let tx = tx.clone();
+            let rx = rx.clone();
+            let busy = busy.clone();
+            let nodes = nodes.clone();
+            let seen_inodes = seen_inodes.clone();
+            let error = error.clone();
+
+            thread::spawn(move || loop {
+                let dir = match rx.recv_timeout(Duration::from_millis(5)) {
+                    Ok(dir) => dir,
+                    // The channel is momentarily empty: only stop once no
+                    // directory handed out earlier is still being processed.
+                    Err(_) if busy.load(Ordering::SeqCst) == 0 => break,
+                    Err(_) => continue,
+                };
+
+                if let Err(err) = visit_dir(&dir, &tx, &busy, &nodes, &seen_inodes, use_cache) {
+                    *error.lock().unwrap() = Some(err);
+                }
+                busy.fetch_sub(1, Ordering::SeqCst);
+            })
+        })
+        .collect();
+
+    drop(tx);
+    for worker in workers {
+        let _ = worker.join();
+    }
+
+    if let Some(err) = error.lock().unwrap().take() {
+        return Err(err);
+    }
+
+    Ok(nodes
+        .lock()
+        .unwrap()
+        .get(path)
+        .map(|node| node.stats)
+        .unwrap_or_default())
+}
+
+/// Counts the number of files and the total apparent size of all files within
+/// the given `path`. File size is counted as the actual size in bytes, i.e.
+/// the size returned by [std::path::Path::metadata].
+///
+/// This is a pre-existing helper whose callers expect a fresh measurement of
+/// `path` every time, so — like [`newest_mtime`] — it uses
+/// [`dir_stats_uncached`] rather than the memoized [`dir_stats`]. Prefer
+/// [`dir_stats`] directly if you want repeated queries over the same tree
+/// to be served from cache (and remember to [`clear_cache`] once it changes).
+///
+/// Returns (file_count, size).
+pub fn get_file_count_and_size(path: &Path) -> io::Result<(u64, u64)> {
+    let stats = dir_stats_uncached(path)?;
+    Ok((stats.file_count, stats.apparent_size))
+}
+
+/// Counts the number of files, their total apparent size and the newest
+/// modification time (seconds since the Unix epoch) found anywhere within
+/// `path`, in a single traversal. Uses [`dir_stats_uncached`], for the same
+/// reason [`newest_mtime`] does.
+///
+/// Returns (file_count, size, newest_mtime).
+pub fn get_file_count_size_and_mtime(path: &Path) -> io::Result<(u64, u64, u64)> {
+    let stats = dir_stats_uncached(path)?;
+    Ok((stats.file_count, stats.apparent_size, stats.newest_mtime))
+}
+
+/// Returns the modification time of the most recently modified file
+/// anywhere within `path`, as seconds since the Unix epoch, or 0 on error.
+/// This lets callers tell whether a checkout changed since the last
+/// [`touch_all`] without re-touching it.
+///
+/// Uses [`dir_stats_uncached`] rather than [`dir_stats`] — see its doc
+/// comment for why a cached result can't be used here. Every call therefore
+/// walks the whole tree afresh; nothing is served from the [`dir_stats`]
+/// cache.
+pub fn newest_mtime(path: &Path) -> u64 {
+    dir_stats_uncached(path)
+        .map(|stats| stats.newest_mtime)
+        .unwrap_or(0)
 }
 
 #[cfg(windows)]
@@ -168,4 +806,20 @@ mod tests {
         assert_eq!(files, 6);
         assert_eq!(size, 1024 + 16 + 32 + 64 + 64 + 128);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dir_stats_dedupes_hard_links() {
+        use super::dir_stats;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let root = dir.path();
+
+        std::fs::write(root.join("a.rs"), vec![0u8; 64]).unwrap();
+        std::fs::hard_link(root.join("a.rs"), root.join("b.rs")).unwrap();
+
+        let stats = dir_stats(root).unwrap();
+        assert_eq!(stats.file_count, 1);
+        assert_eq!(stats.apparent_size, 64);
+    }
 }